@@ -1,20 +1,41 @@
-use fst::Streamer;
-use std::mem;
+use crossbeam;
+use fst::{Automaton, Streamer};
+use fst::automaton::AlwaysMatch;
+use fst::map::{Stream as FstStream, StreamBuilder};
+use smallvec::SmallVec;
+use std::cmp;
 use std::collections::BinaryHeap;
-use fst::map::Keys;
+use std::ops::Bound;
 use schema::Term;
 use core::SegmentReader;
+use postings::{TermInfo, TermInfoStore};
 use std::cmp::Ordering;
 
 
 static EMPTY: [u8; 0] = [];
 
-#[derive(PartialEq, Eq, Debug)]
+/// Number of term bytes stored inline in a `HeapItem` before spilling to
+/// the heap. Most indexed terms (tokens, numbers, short identifiers) fit
+/// comfortably under this, so the common case of pushing a candidate term
+/// onto the merge heap does not allocate.
+const INLINE_TERM_BYTES: usize = 24;
+
+type TermBytes = SmallVec<[u8; INLINE_TERM_BYTES]>;
+
 struct HeapItem {
-    term: Term,
+    term: TermBytes,
     segment_ord: usize,
+    term_info: TermInfo,
+}
+
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &HeapItem) -> bool {
+        self.term == other.term && self.segment_ord == other.segment_ord
+    }
 }
 
+impl Eq for HeapItem {}
+
 impl PartialOrd for HeapItem {
     fn partial_cmp(&self, other: &HeapItem) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -27,27 +48,58 @@ impl Ord for HeapItem {
     }
 }
 
+/// Applies the given lower/upper bounds to a `StreamBuilder`, respecting
+/// inclusive/exclusive semantics, before any segment stream is built.
+fn bounded_stream_builder<'a, A: Automaton>(mut stream_builder: StreamBuilder<'a, A>,
+                                             lower_bound: Bound<&[u8]>,
+                                             upper_bound: Bound<&[u8]>)
+                                             -> StreamBuilder<'a, A> {
+    stream_builder = match lower_bound {
+        Bound::Included(bound) => stream_builder.ge(bound),
+        Bound::Excluded(bound) => stream_builder.gt(bound),
+        Bound::Unbounded => stream_builder,
+    };
+    stream_builder = match upper_bound {
+        Bound::Included(bound) => stream_builder.le(bound),
+        Bound::Excluded(bound) => stream_builder.lt(bound),
+        Bound::Unbounded => stream_builder,
+    };
+    stream_builder
+}
+
 /// Given a list of sorted term streams,
 /// returns an iterator over sorted unique terms.
 ///
 /// The item yield is actually a pair with
 /// - the term
-/// - a slice with the ordinal of the segments containing
-/// the terms.
-pub struct TermIterator<'a> {
-    key_streams: Vec<Keys<'a>>,
+/// - a slice with, for each segment containing the term, its ordinal
+/// together with the `TermInfo` resolved from that segment's
+/// `TermInfoStore` (the FST only carries the offset into the store), so
+/// that a caller can union the term's postings across segments without a
+/// second dictionary lookup.
+pub struct TermIterator<'a, A = AlwaysMatch>
+    where A: Automaton
+{
+    key_streams: Vec<FstStream<'a, A>>,
+    // One term-info store per segment, indexed the same way as
+    // `key_streams`: the FST only gives us an offset into this store,
+    // not a ready-to-use `TermInfo`.
+    term_info_stores: Vec<&'a TermInfoStore>,
     heap: BinaryHeap<HeapItem>,
-    // Buffer hosting the list of segment ordinals containing
-    // the current term.
+    // Buffer hosting the list of (segment ordinal, term info) pairs
+    // for the segments containing the current term.
     current_term: Term,
-    current_segment_ords: Vec<usize>,
+    current_segment_ords: Vec<(usize, TermInfo)>,
 }
 
-impl<'a> TermIterator<'a> {
-    fn new(key_streams: Vec<Keys<'a>>) -> TermIterator<'a> {
+impl<'a, A: Automaton> TermIterator<'a, A> {
+    fn new(key_streams: Vec<FstStream<'a, A>>,
+           term_info_stores: Vec<&'a TermInfoStore>)
+           -> TermIterator<'a, A> {
         let key_streams_len = key_streams.len();
         let mut term_iterator = TermIterator {
             key_streams: key_streams,
+            term_info_stores: term_info_stores,
             heap: BinaryHeap::new(),
             current_term: Term::from(&EMPTY[..]),
             current_segment_ords: vec![],
@@ -59,29 +111,91 @@ impl<'a> TermIterator<'a> {
     }
 
     fn push_next_segment_el(&mut self, segment_ord: usize) {
-        self.current_segment_ords.push(segment_ord);
-        if let Some(term) = self.key_streams[segment_ord].next() {
+        if let Some((term, term_info_offset)) = self.key_streams[segment_ord].next() {
+            // `fst`'s streamer invalidates the key it just returned as soon
+            // as `next()` is called again, so this copy into the (usually
+            // stack-resident) inline buffer cannot be avoided. What it does
+            // avoid is a heap allocation per candidate pushed onto the
+            // merge heap.
+            let term_info = self.term_info_stores[segment_ord].get(term_info_offset);
             self.heap.push(HeapItem {
-                term: Term::from(term),
+                term: TermBytes::from_slice(term),
                 segment_ord: segment_ord,
+                term_info: term_info,
             });
         }
     }
 }
 
-impl<'a, 'f> Streamer<'a> for TermIterator<'f> {
-    type Item = (&'a Term, &'a [usize]);
+impl<'a> TermIterator<'a, AlwaysMatch> {
+    /// Returns a `TermIterator` over every term of the given segments,
+    /// restricted to the range between `lower_bound` and `upper_bound`
+    /// (bounds may each independently be inclusive, exclusive or unbounded).
+    ///
+    /// The bounds are applied to each segment's term dictionary before
+    /// the merge starts, so no term outside of the range is ever pushed
+    /// onto the heap.
+    pub fn with_range(segment_readers: &'a [SegmentReader],
+                       lower_bound: Bound<&[u8]>,
+                       upper_bound: Bound<&[u8]>)
+                       -> TermIterator<'a, AlwaysMatch> {
+        let key_streams = segment_readers
+            .iter()
+            .map(|reader| {
+                     bounded_stream_builder(reader.term_infos().range(),
+                                             lower_bound,
+                                             upper_bound)
+                             .into_stream()
+                 })
+            .collect();
+        let term_info_stores = segment_readers
+            .iter()
+            .map(|reader| reader.term_info_store())
+            .collect();
+        TermIterator::new(key_streams, term_info_stores)
+    }
+}
+
+impl<'a, A: Automaton + Clone + 'a> TermIterator<'a, A> {
+    /// Returns a `TermIterator` over every term of the given segments that
+    /// is accepted by `automaton` (e.g. a Levenshtein or regex automaton),
+    /// unioning the matches across all segments in a single merged pass.
+    pub fn with_automaton(segment_readers: &'a [SegmentReader],
+                          automaton: A)
+                          -> TermIterator<'a, A> {
+        let key_streams = segment_readers
+            .iter()
+            .map(|reader| reader.term_infos().search(automaton.clone()).into_stream())
+            .collect();
+        let term_info_stores = segment_readers
+            .iter()
+            .map(|reader| reader.term_info_store())
+            .collect();
+        TermIterator::new(key_streams, term_info_stores)
+    }
+}
+
+impl<'a, 'f, A: Automaton> Streamer<'a> for TermIterator<'f, A> {
+    type Item = (&'a Term, &'a [(usize, TermInfo)]);
 
     fn next(&'a mut self) -> Option<Self::Item> {
         self.current_segment_ords.clear();
         self.heap
             .pop()
-            .map(move |mut head| {
-                mem::swap(&mut self.current_term, &mut head.term);
+            .map(move |head| {
+                // The inline `TermBytes` buffer only removes the
+                // allocation for candidates pushed onto the heap. Building
+                // the `Term` we actually hand back to the caller still
+                // allocates, once per distinct merged term rather than
+                // once per (term, segment) heap push - a large reduction,
+                // not a total elimination.
+                self.current_term = Term::from(head.term.as_slice());
+                self.current_segment_ords.push((head.segment_ord, head.term_info));
                 self.push_next_segment_el(head.segment_ord);
                 loop {
                     match self.heap.peek() {
-                        Some(&ref next_heap_it) if next_heap_it.term == self.current_term => {}
+                        Some(&ref next_heap_it) if next_heap_it.term.as_slice() ==
+                                                    self.current_term.value() => {}
                         _ => {
                             break;
                         }
@@ -90,6 +204,7 @@ impl<'a, 'f> Streamer<'a> for TermIterator<'f> {
                                            .pop()
                                            .expect("This is only reached if an element was \
                                                     peeked beforehand.");
+                    self.current_segment_ords.push((next_heap_it.segment_ord, next_heap_it.term_info));
                     self.push_next_segment_el(next_heap_it.segment_ord);
                 }
                 (&self.current_term, self.current_segment_ords.as_slice())
@@ -97,12 +212,105 @@ impl<'a, 'f> Streamer<'a> for TermIterator<'f> {
     }
 }
 
-impl<'a> From<&'a [SegmentReader]> for TermIterator<'a> {
-    fn from(segment_readers: &'a [SegmentReader]) -> TermIterator<'a> {
-        TermIterator::new(segment_readers.iter()
-                                         .map(|reader| reader.term_infos().keys())
-                                         .collect())
+impl<'a> From<&'a [SegmentReader]> for TermIterator<'a, AlwaysMatch> {
+    fn from(segment_readers: &'a [SegmentReader]) -> TermIterator<'a, AlwaysMatch> {
+        TermIterator::with_range(segment_readers, Bound::Unbounded, Bound::Unbounded)
+    }
+}
+
+/// Samples up to `num_partitions - 1` split points from the largest
+/// segment's term dictionary, evenly spaced, to use as the boundaries of a
+/// parallel range-partitioned merge.
+///
+/// This is a cheap approximation: the sample is taken from a single
+/// segment, so the resulting partitions are not guaranteed to be
+/// perfectly balanced across the merged dictionary, only close to it.
+fn sample_split_points(segment_readers: &[SegmentReader], num_partitions: usize) -> Vec<Vec<u8>> {
+    if num_partitions <= 1 {
+        return Vec::new();
+    }
+    let largest_term_infos = segment_readers
+        .iter()
+        .map(|reader| reader.term_infos())
+        .max_by_key(|term_infos| term_infos.len());
+    let largest_term_infos = match largest_term_infos {
+        Some(term_infos) if term_infos.len() > 0 => term_infos,
+        _ => return Vec::new(),
+    };
+    let stride = cmp::max(1, largest_term_infos.len() / num_partitions);
+    let mut split_points = Vec::with_capacity(num_partitions - 1);
+    let mut stream = largest_term_infos.stream();
+    let mut seen = 0usize;
+    while let Some((term, _)) = stream.next() {
+        seen += 1;
+        if split_points.len() < num_partitions - 1 && seen % stride == 0 {
+            split_points.push(term.to_vec());
+        }
+    }
+    split_points
+}
+
+/// Turns a sorted list of split points into the half-open `[lo, hi)` bounds
+/// of the partitions they delimit. Each partition includes its lower bound
+/// and excludes its upper bound, so a term landing exactly on a split point
+/// is routed to the partition that starts there (the one immediately
+/// after the split point), not the one it serves as the exclusive upper
+/// bound for.
+fn partition_bounds(split_points: &[Vec<u8>]) -> Vec<(Bound<&[u8]>, Bound<&[u8]>)> {
+    let mut bounds = Vec::with_capacity(split_points.len() + 1);
+    let mut lower_bound = Bound::Unbounded;
+    for split_point in split_points {
+        bounds.push((lower_bound, Bound::Excluded(split_point.as_slice())));
+        lower_bound = Bound::Included(split_point.as_slice());
+    }
+    bounds.push((lower_bound, Bound::Unbounded));
+    bounds
+}
+
+fn merge_partition(segment_readers: &[SegmentReader],
+                    lower_bound: Bound<&[u8]>,
+                    upper_bound: Bound<&[u8]>)
+                    -> Vec<(Term, Vec<(usize, TermInfo)>)> {
+    let mut term_it = TermIterator::with_range(segment_readers, lower_bound, upper_bound);
+    let mut merged_terms = Vec::new();
+    while let Some((term, segment_term_infos)) = term_it.next() {
+        merged_terms.push((term.clone(), segment_term_infos.to_vec()));
     }
+    merged_terms
+}
+
+/// Merges the term dictionaries of `segment_readers` using up to
+/// `num_partitions` worker threads.
+///
+/// The keyspace is split into up to `num_partitions` contiguous, disjoint,
+/// ordered ranges (see `sample_split_points`; a small or empty dictionary
+/// yields fewer ranges), and each range is merged
+/// independently by `TermIterator::with_range` on its own thread. Because
+/// the ranges are disjoint and ordered, the per-partition results can
+/// simply be concatenated in partition order to recover the fully sorted,
+/// merged term stream - no final merge step is needed.
+pub fn merge_par(segment_readers: &[SegmentReader],
+                  num_partitions: usize)
+                  -> Vec<(Term, Vec<(usize, TermInfo)>)> {
+    let num_partitions = cmp::max(1, num_partitions);
+    let split_points = sample_split_points(segment_readers, num_partitions);
+    let bounds = partition_bounds(&split_points);
+
+    crossbeam::scope(|scope| {
+        bounds
+            .iter()
+            .map(|&(lower_bound, upper_bound)| {
+                     scope.spawn(move || merge_partition(segment_readers, lower_bound, upper_bound))
+                 })
+            .collect::<Vec<_>>()
+            .into_iter()
+            // A worker panic means its partition's keyspace range went
+            // unmerged; surface that loudly instead of silently returning
+            // a truncated dictionary.
+            .map(|handle| handle.join().unwrap())
+            .flat_map(|merged_terms| merged_terms.into_iter())
+            .collect()
+    })
 }
 
 #[cfg(test)]
@@ -134,6 +342,15 @@ mod tests {
                     doc.add_text(text_field, "a b c d f");
                     index_writer.add_document(doc).unwrap();
                 }
+                {
+                    // a second document containing "a", so this segment's
+                    // doc_freq for "a" (2) differs from the first
+                    // segment's (1) - this is what exercises the decode
+                    // of the per-segment TermInfo, not just the ordinal.
+                    let mut doc = Document::default();
+                    doc.add_text(text_field, "a");
+                    index_writer.add_document(doc).unwrap();
+                }
                 index_writer.commit().unwrap();
             }
             {
@@ -152,40 +369,170 @@ mod tests {
 
             let (term, segments) = term_it.next().unwrap();
             assert_eq!(term.value(), "a".as_bytes());
-            let expected_segments = [0, 1];
-            assert_eq!(segments, &expected_segments);
+            let segment_ords: Vec<usize> = segments.iter().map(|pair| pair.0).collect();
+            assert_eq!(segment_ords, vec![0, 1]);
+            // "a" appears in one document in segment 0 but two in segment
+            // 1, so the resolved `TermInfo::doc_freq` must differ between
+            // them - this is only true if it was actually decoded through
+            // each segment's own term-info store.
+            let doc_freqs: Vec<u32> = segments.iter().map(|pair| (pair.1).doc_freq).collect();
+            assert_eq!(doc_freqs, vec![1, 2]);
 
         }
         {
-            let (term, segments): (&Term, &[usize]) = term_it.next().unwrap();
+            let (term, segments) = term_it.next().unwrap();
             assert_eq!(term.value(), "b".as_bytes());
-            let expected_segments = [0, 1];
-            assert_eq!(segments, &expected_segments);
+            let segment_ords: Vec<usize> = segments.iter().map(|pair| pair.0).collect();
+            assert_eq!(segment_ords, vec![0, 1]);
         }
         {
             let (ref term, ref segments) = term_it.next().unwrap();
             assert_eq!(term.value(), "c".as_bytes());
-            let expected_segments = [1];
-            assert_eq!(segments, &expected_segments);
+            let segment_ords: Vec<usize> = segments.iter().map(|pair| pair.0).collect();
+            assert_eq!(segment_ords, vec![1]);
         }
         {
             let (term, segments) = term_it.next().unwrap();
             assert_eq!(term.value(), "d".as_bytes());
-            let expected_segments = [0, 1];
-            assert_eq!(segments, &expected_segments);
+            let segment_ords: Vec<usize> = segments.iter().map(|pair| pair.0).collect();
+            assert_eq!(segment_ords, vec![0, 1]);
         }
         {
             let (term, segments) = term_it.next().unwrap();
             assert_eq!(term.value(), "e".as_bytes());
-            let expected_segments = [2];
-            assert_eq!(segments, &expected_segments);
+            let segment_ords: Vec<usize> = segments.iter().map(|pair| pair.0).collect();
+            assert_eq!(segment_ords, vec![2]);
         }
         {
             let (term, segments) = term_it.next().unwrap();
             assert_eq!(term.value(), "f".as_bytes());
-            let expected_segments = [0, 1, 2];
-            assert_eq!(segments, &expected_segments);
+            let segment_ords: Vec<usize> = segments.iter().map(|pair| pair.0).collect();
+            assert_eq!(segment_ords, vec![0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn test_term_iterator_with_range() {
+        let mut schema_builder = SchemaBuilder::default();
+        let text_field = schema_builder.add_text_field("text", TEXT);
+        let index = Index::create_in_ram(schema_builder.build());
+        {
+            let mut index_writer = index.writer_with_num_threads(1, 40_000_000).unwrap();
+            let mut doc = Document::default();
+            doc.add_text(text_field, "a b c d f");
+            index_writer.add_document(doc).unwrap();
+            index_writer.commit().unwrap();
         }
+        let searcher = index.searcher();
+        let segment_readers = searcher.segment_readers();
+        let mut term_it = TermIterator::with_range(segment_readers,
+                                                     Bound::Included("b".as_bytes()),
+                                                     Bound::Excluded("d".as_bytes()));
+        let (term, _) = term_it.next().unwrap();
+        assert_eq!(term.value(), "b".as_bytes());
+        let (term, _) = term_it.next().unwrap();
+        assert_eq!(term.value(), "c".as_bytes());
+        assert!(term_it.next().is_none());
+    }
+
+    /// A minimal prefix-matching automaton, used to exercise
+    /// `TermIterator::with_automaton` without depending on a specific
+    /// automaton implementation shipping in `fst`.
+    #[derive(Clone)]
+    struct PrefixMatcher<'p> {
+        prefix: &'p [u8],
+    }
+
+    impl<'p> Automaton for PrefixMatcher<'p> {
+        type State = usize;
+
+        fn start(&self) -> usize {
+            0
+        }
+
+        fn is_match(&self, state: &usize) -> bool {
+            *state == self.prefix.len()
+        }
+
+        fn can_match(&self, state: &usize) -> bool {
+            *state != usize::max_value()
+        }
+
+        fn accept(&self, state: &usize, byte: u8) -> usize {
+            if *state == usize::max_value() {
+                return usize::max_value();
+            }
+            if *state == self.prefix.len() {
+                // Prefix already matched: any continuation still matches.
+                return *state;
+            }
+            if self.prefix[*state] == byte {
+                *state + 1
+            } else {
+                usize::max_value()
+            }
+        }
+    }
+
+    #[test]
+    fn test_term_iterator_with_automaton() {
+        let mut schema_builder = SchemaBuilder::default();
+        let text_field = schema_builder.add_text_field("text", TEXT);
+        let index = Index::create_in_ram(schema_builder.build());
+        {
+            let mut index_writer = index.writer_with_num_threads(1, 40_000_000).unwrap();
+            {
+                let mut doc = Document::default();
+                doc.add_text(text_field, "car cart dog");
+                index_writer.add_document(doc).unwrap();
+                index_writer.commit().unwrap();
+            }
+            {
+                let mut doc = Document::default();
+                doc.add_text(text_field, "care cat dog");
+                index_writer.add_document(doc).unwrap();
+                index_writer.commit().unwrap();
+            }
+        }
+        let searcher = index.searcher();
+        let segment_readers = searcher.segment_readers();
+        let automaton = PrefixMatcher { prefix: "car".as_bytes() };
+        let mut term_it = TermIterator::with_automaton(segment_readers, automaton);
+        let mut matched_terms = Vec::new();
+        while let Some((term, _)) = term_it.next() {
+            matched_terms.push(term.value().to_vec());
+        }
+        let expected: Vec<Vec<u8>> = ["car", "care", "cart"]
+            .iter()
+            .map(|term| term.as_bytes().to_vec())
+            .collect();
+        assert_eq!(matched_terms, expected);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_merge_par() {
+        let mut schema_builder = SchemaBuilder::default();
+        let text_field = schema_builder.add_text_field("text", TEXT);
+        let index = Index::create_in_ram(schema_builder.build());
+        {
+            let mut index_writer = index.writer_with_num_threads(1, 40_000_000).unwrap();
+            let mut doc = Document::default();
+            doc.add_text(text_field, "a b c d e f g h");
+            index_writer.add_document(doc).unwrap();
+            index_writer.commit().unwrap();
+        }
+        let searcher = index.searcher();
+        let segment_readers = searcher.segment_readers();
+        let merged_terms = merge_par(segment_readers, 4);
+        let terms: Vec<Vec<u8>> = merged_terms
+            .iter()
+            .map(|&(ref term, _)| term.value().to_vec())
+            .collect();
+        let expected: Vec<Vec<u8>> = ["a", "b", "c", "d", "e", "f", "g", "h"]
+            .iter()
+            .map(|term| term.as_bytes().to_vec())
+            .collect();
+        assert_eq!(terms, expected);
+    }
+
+}